@@ -1,94 +1,309 @@
 use super::test_highlight;
+use serde::Serialize;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::fmt;
 use std::fmt::Write;
 use std::io;
 use std::io::ErrorKind;
 use tree_sitter::{QueryError, QueryErrorKind};
-use walkdir;
 
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A stable code for a `Diagnostic`, part of the `--error-format=json` contract.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiagnosticKind {
+    InvalidCapture,
+    InvalidField,
+    InvalidNodeType,
+    InvalidSyntax,
+    ImpossiblePattern,
+    InvalidPredicate,
+    Grammar,
+    Regex,
+    UndefinedSymbol,
+    Other,
+}
+
+/// How serious a diagnostic is; only `Error` affects the process exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Ignored,
+    Note,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+            Severity::Ignored => write!(f, "ignored"),
+        }
+    }
+}
+
+/// A serializable rendering of an `Error`, for `--error-format=json`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: DiagnosticKind,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub row: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct Location {
+    path: String,
+    row: usize,
+    column: usize,
+    offset: usize,
+}
+
+/// An error from one of the CLI's subcommands; boxed so `Result<T, Error>`
+/// stays pointer-sized.
 #[derive(Debug)]
-pub struct Error(Option<Vec<String>>);
+pub struct Error(Box<ErrorInner>);
 
-pub type Result<T> = std::result::Result<T, Error>;
+#[derive(Debug)]
+struct ErrorInner {
+    message: String,
+    source: Option<Box<dyn std::error::Error + Send + Sync + 'static>>,
+    backtrace: Option<Backtrace>,
+    severity: Severity,
+    kind: DiagnosticKind,
+    location: Option<Location>,
+}
 
 impl Error {
     pub fn grammar(message: &str) -> Self {
-        Error(Some(vec![format!("Grammar error: {}", message)]))
+        Error::new(format!("Grammar error: {}", message)).with_kind(DiagnosticKind::Grammar)
     }
 
     pub fn regex(mut message: String) -> Self {
         message.insert_str(0, "Regex error: ");
-        Error(Some(vec![message]))
+        Error::new(message).with_kind(DiagnosticKind::Regex)
     }
 
     pub fn undefined_symbol(name: &str) -> Self {
-        Error(Some(vec![format!("Undefined symbol `{}`", name)]))
+        Error::new(format!("Undefined symbol `{}`", name))
+            .with_kind(DiagnosticKind::UndefinedSymbol)
     }
 
     pub fn new(message: String) -> Self {
-        Error(Some(vec![message]))
+        Self(Box::new(ErrorInner {
+            message,
+            source: None,
+            backtrace: Self::capture_backtrace(),
+            severity: Severity::Error,
+            kind: DiagnosticKind::Other,
+            location: None,
+        }))
+    }
+
+    pub fn from_source<E: std::error::Error + Send + Sync + 'static>(
+        message: String,
+        source: E,
+    ) -> Self {
+        let mut error = Error::new(message);
+        error.0.source = Some(Box::new(source));
+        error
     }
 
     pub fn new_ignored() -> Self {
-        Self(None)
+        Self(Box::new(ErrorInner {
+            message: String::new(),
+            source: None,
+            backtrace: None,
+            severity: Severity::Ignored,
+            kind: DiagnosticKind::Other,
+            location: None,
+        }))
+    }
+
+    fn with_kind(mut self, kind: DiagnosticKind) -> Self {
+        self.0.kind = kind;
+        self
+    }
+
+    pub fn with_severity(mut self, severity: Severity) -> Self {
+        self.0.severity = severity;
+        self
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.0.severity
+    }
+
+    fn with_location(mut self, path: String, row: usize, column: usize, offset: usize) -> Self {
+        self.0.location = Some(Location {
+            path,
+            row,
+            column,
+            offset,
+        });
+        self
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic {
+            severity: self.0.severity,
+            code: self.0.kind,
+            message: self.0.message.clone(),
+            path: self.0.location.as_ref().map(|l| l.path.clone()),
+            row: self.0.location.as_ref().map(|l| l.row),
+            column: self.0.location.as_ref().map(|l| l.column),
+            offset: self.0.location.as_ref().map(|l| l.offset),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&self.diagnostic())
+    }
+
+    /// Like `message()`, but appends a caret snippet of `source` when a
+    /// location is available.
+    pub fn render_with_source(&self, source: &str) -> String {
+        let mut result = self.message();
+        if let Some(location) = &self.0.location {
+            if let Some(snippet) = render_snippet(source, location.row, location.column) {
+                result.push('\n');
+                result.push_str(&snippet);
+            }
+        }
+        result
     }
 
     pub fn is_ignored(&self) -> bool {
-        self.0.is_none()
+        self.0.severity == Severity::Ignored
     }
 
     pub fn err<T>(message: String) -> Result<T> {
         Err(Error::new(message))
     }
 
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        self.0.backtrace.as_ref()
+    }
+
+    /// Attaches a new head message, demoting `self` to `source()`. Location,
+    /// kind and severity carry forward, since `diagnostic()` only reads them
+    /// off the outermost `Error`.
+    pub fn context<M: ToString>(self, message: M) -> Self {
+        if self.is_ignored() {
+            panic!("It's not allowed to wrap an ignored error");
+        }
+        let severity = self.0.severity;
+        let kind = self.0.kind;
+        let location = self.0.location.clone();
+        let mut result = Error::new(message.to_string());
+        result.0.severity = severity;
+        result.0.kind = kind;
+        result.0.location = location;
+        result.0.source = Some(Box::new(self));
+        result
+    }
+
     pub fn wrap<E: Into<Self>, M: ToString, F: FnOnce() -> M>(
         message_fn: F,
     ) -> impl FnOnce(E) -> Self {
-        |e| {
-            let mut result = e.into();
-            match result.0 {
-                Some(ref mut e) => e.push(message_fn().to_string()),
-                None => panic!("It's not allowed to wrap an ignored error"),
-            }
-            result
-        }
+        |e| e.into().context(message_fn())
     }
 
     pub fn message(&self) -> String {
-        match self.0 {
-            None => "Ignored error".to_string(),
-            Some(ref e) => {
-                let mut result = e.last().unwrap().clone();
-                if e.len() > 1 {
-                    result.push_str("\nDetails:\n");
-                    for msg in e[0..e.len() - 1].iter().rev() {
-                        writeln!(&mut result, "  {}", msg).unwrap();
-                    }
-                }
-                result
-            }
+        if self.is_ignored() {
+            return "Ignored error".to_string();
+        }
+        let mut result = self.0.message.clone();
+        let mut source = std::error::Error::source(self);
+        if source.is_some() {
+            result.push_str("\nCaused by:\n");
+        }
+        while let Some(err) = source {
+            writeln!(&mut result, "  {}", err).unwrap();
+            source = err.source();
+        }
+        result
+    }
+
+    fn capture_backtrace() -> Option<Backtrace> {
+        let backtrace = Backtrace::capture();
+        match backtrace.status() {
+            BacktraceStatus::Captured => Some(backtrace),
+            _ => None,
         }
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ignored() {
+            return write!(f, "Ignored error");
+        }
+        write!(f, "{}", self.0.message)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0
+            .source
+            .as_ref()
+            .map(|e| e.as_ref() as &(dyn std::error::Error + 'static))
+    }
+}
+
 impl<'a> From<(&str, QueryError)> for Error {
     fn from((path, error): (&str, QueryError)) -> Self {
         let mut msg = format!("Query error at {}:{}. ", path, error.row + 1);
-        match error.kind {
-            QueryErrorKind::Capture => write!(&mut msg, "Invalid capture name {}", error.message),
-            QueryErrorKind::Field => write!(&mut msg, "Invalid field name {}", error.message),
-            QueryErrorKind::NodeType => write!(&mut msg, "Invalid node type {}", error.message),
-            QueryErrorKind::Syntax => write!(&mut msg, "Invalid syntax:\n{}", error.message),
-            QueryErrorKind::Structure => write!(&mut msg, "Impossible pattern:\n{}", error.message),
-            QueryErrorKind::Predicate => write!(&mut msg, "Invalid predicate: {}", error.message),
-        }
-        .unwrap();
-        Self::new(msg)
+        let kind = match error.kind {
+            QueryErrorKind::Capture => {
+                write!(&mut msg, "Invalid capture name {}", error.message).unwrap();
+                DiagnosticKind::InvalidCapture
+            }
+            QueryErrorKind::Field => {
+                write!(&mut msg, "Invalid field name {}", error.message).unwrap();
+                DiagnosticKind::InvalidField
+            }
+            QueryErrorKind::NodeType => {
+                write!(&mut msg, "Invalid node type {}", error.message).unwrap();
+                DiagnosticKind::InvalidNodeType
+            }
+            QueryErrorKind::Syntax => {
+                write!(&mut msg, "Invalid syntax:\n{}", error.message).unwrap();
+                DiagnosticKind::InvalidSyntax
+            }
+            QueryErrorKind::Structure => {
+                write!(&mut msg, "Impossible pattern:\n{}", error.message).unwrap();
+                DiagnosticKind::ImpossiblePattern
+            }
+            QueryErrorKind::Predicate => {
+                write!(&mut msg, "Invalid predicate: {}", error.message).unwrap();
+                DiagnosticKind::InvalidPredicate
+            }
+        };
+        let (row, column, offset) = (error.row, error.column, error.offset);
+        Self::from_source(msg, error)
+            .with_kind(kind)
+            .with_location(path.to_string(), row, column, offset)
     }
 }
 
 impl<'a> From<tree_sitter_highlight::Error> for Error {
     fn from(error: tree_sitter_highlight::Error) -> Self {
-        Error::new(format!("{:?}", error))
+        // A bad highlight capture is recoverable: the rest of the file still
+        // highlights fine, so this is a warning rather than a hard error.
+        Error::new(format!("{:?}", error)).with_severity(Severity::Warning)
     }
 }
 
@@ -100,41 +315,53 @@ impl<'a> From<tree_sitter_tags::Error> for Error {
 
 impl From<serde_json::Error> for Error {
     fn from(error: serde_json::Error) -> Self {
-        Error::new(error.to_string())
+        let message = error.to_string();
+        Error::from_source(message, error)
     }
 }
 
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Self {
-        match error {
-            x if x.kind() == ErrorKind::BrokenPipe => return Error::new_ignored(),
-            _ => (),
+        if error.kind() == ErrorKind::BrokenPipe {
+            return Error::new_ignored();
         }
-        Error::new(error.to_string())
+        let message = error.to_string();
+        Error::from_source(message, error)
+    }
+}
+
+impl From<ignore::Error> for Error {
+    fn from(error: ignore::Error) -> Self {
+        let message = error.to_string();
+        Error::from_source(message, error)
     }
 }
 
 impl From<glob::PatternError> for Error {
     fn from(error: glob::PatternError) -> Self {
-        Error::new(error.to_string())
+        let message = error.to_string();
+        Error::from_source(message, error)
     }
 }
 
 impl From<glob::GlobError> for Error {
     fn from(error: glob::GlobError) -> Self {
-        Error::new(error.to_string())
+        let message = error.to_string();
+        Error::from_source(message, error)
     }
 }
 
 impl From<libloading::Error> for Error {
     fn from(error: libloading::Error) -> Self {
-        Error::new(error.to_string())
+        let message = error.to_string();
+        Error::from_source(message, error)
     }
 }
 
 impl From<regex_syntax::ast::Error> for Error {
     fn from(error: regex_syntax::ast::Error) -> Self {
-        Error::new(error.to_string())
+        let message = error.to_string();
+        Error::from_source(message, error)
     }
 }
 
@@ -150,8 +377,186 @@ impl From<String> for Error {
     }
 }
 
-impl From<walkdir::Error> for Error {
-    fn from(error: walkdir::Error) -> Self {
-        Error::new(error.to_string())
+/// Renders a rustc/just-style caret diagnostic: a line-number gutter, the
+/// offending line, and a `^^^` underline spanning the token at `column` (a
+/// byte offset into that line), clamped to the line end.
+fn render_snippet(source: &str, row: usize, column: usize) -> Option<String> {
+    let line = source.lines().nth(row)?;
+    let rendered_line: String = line
+        .chars()
+        .map(|c| if c == '\t' { ' ' } else { c })
+        .collect();
+    let chars: Vec<char> = rendered_line.chars().collect();
+    let char_count = chars.len();
+    let caret_column = line
+        .char_indices()
+        .position(|(i, _)| i >= column)
+        .unwrap_or(char_count)
+        .min(char_count);
+    let available = char_count - caret_column;
+    let token_len = chars[caret_column..]
+        .iter()
+        .take_while(|c| !c.is_whitespace())
+        .count();
+    let span_len = token_len.max(1).min(available.max(1));
+    let gutter = format!("{} | ", row + 1);
+    let mut result = String::new();
+    writeln!(&mut result, "{}{}", gutter, rendered_line).ok()?;
+    write!(
+        &mut result,
+        "{}{}{}",
+        " ".repeat(gutter.chars().count()),
+        " ".repeat(caret_column),
+        "^".repeat(span_len),
+    )
+    .ok()?;
+    Some(result)
+}
+
+/// Collects many independent `Error`s, so linting a whole directory of
+/// grammars/queries can report every malformed file instead of aborting on
+/// the first one.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    items: Vec<Error>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self { items: Vec::new() }
+    }
+
+    pub fn push(&mut self, error: Error) {
+        if !error.is_ignored() {
+            self.items.push(error);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.items.iter().any(|e| e.0.severity == Severity::Error)
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.items
+            .iter()
+            .filter(|e| e.0.severity == severity)
+            .count()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Error> {
+        self.items.iter()
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        i32::from(self.has_errors())
+    }
+
+    /// Renders every diagnostic grouped by path (even if pushes interleaved
+    /// across files), followed by a summary line like "3 errors, 2 warnings".
+    pub fn message(&self) -> String {
+        let mut groups: Vec<(Option<&str>, Vec<&Error>)> = Vec::new();
+        for error in &self.items {
+            let path = error.0.location.as_ref().map(|l| l.path.as_str());
+            match groups.iter_mut().find(|(p, _)| *p == path) {
+                Some((_, group)) => group.push(error),
+                None => groups.push((path, vec![error])),
+            }
+        }
+
+        let mut result = String::new();
+        for (path, group) in groups {
+            if let Some(path) = path {
+                writeln!(&mut result, "{}:", path).unwrap();
+            }
+            for error in group {
+                writeln!(&mut result, "  {}: {}", error.0.severity, error.message()).unwrap();
+            }
+        }
+        let errors = self.count(Severity::Error);
+        let warnings = self.count(Severity::Warning);
+        write!(
+            &mut result,
+            "{} error{}, {} warning{}",
+            errors,
+            if errors == 1 { "" } else { "s" },
+            warnings,
+            if warnings == 1 { "" } else { "s" },
+        )
+        .unwrap();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_snippet_underlines_the_whole_token() {
+        let snippet = render_snippet("abc def\n", 0, 4).unwrap();
+        assert_eq!(snippet, "1 | abc def\n        ^^^");
+    }
+
+    #[test]
+    fn render_snippet_expands_tabs_to_keep_the_underline_aligned() {
+        let snippet = render_snippet("\tfoo\n", 0, 1).unwrap();
+        assert_eq!(snippet, "1 |  foo\n     ^^^");
+    }
+
+    #[test]
+    fn render_snippet_clamps_a_caret_at_the_end_of_the_line() {
+        let snippet = render_snippet("ab\n", 0, 1).unwrap();
+        assert_eq!(snippet, "1 | ab\n     ^");
+    }
+
+    #[test]
+    fn diagnostics_message_groups_interleaved_paths_together() {
+        let mut diagnostics = Diagnostics::new();
+        diagnostics.push(Error::new("a1".to_string()).with_location("a.scm".to_string(), 0, 0, 0));
+        diagnostics.push(Error::new("b1".to_string()).with_location("b.scm".to_string(), 0, 0, 0));
+        diagnostics.push(Error::new("a2".to_string()).with_location("a.scm".to_string(), 1, 0, 0));
+
+        let message = diagnostics.message();
+        assert_eq!(message.matches("a.scm:").count(), 1);
+        assert_eq!(message.matches("b.scm:").count(), 1);
+        let a_header = message.find("a.scm:").unwrap();
+        let a1 = message.find("a1").unwrap();
+        let a2 = message.find("a2").unwrap();
+        let b_header = message.find("b.scm:").unwrap();
+        // Both of a.scm's errors land together under its one header, even
+        // though b.scm's error was pushed in between them.
+        assert!(a_header < a1 && a1 < a2);
+        // b.scm's header must not have been inserted between a.scm's two
+        // errors -- that would mean they got split into separate groups.
+        assert!(!(a_header < b_header && b_header < a2));
+    }
+
+    #[test]
+    fn context_preserves_location_kind_and_severity() {
+        let original = Error::new("bad capture".to_string())
+            .with_kind(DiagnosticKind::InvalidCapture)
+            .with_severity(Severity::Warning)
+            .with_location("query.scm".to_string(), 2, 3, 40);
+
+        let wrapped = original.context("while checking query.scm");
+
+        assert_eq!(wrapped.severity(), Severity::Warning);
+        let diagnostic = wrapped.diagnostic();
+        assert_eq!(diagnostic.code, DiagnosticKind::InvalidCapture);
+        assert_eq!(diagnostic.path.as_deref(), Some("query.scm"));
+        assert_eq!(diagnostic.row, Some(2));
+        assert_eq!(diagnostic.column, Some(3));
+        assert_eq!(diagnostic.offset, Some(40));
+    }
+
+    #[test]
+    fn new_ignored_errors_report_ignored_severity() {
+        let error = Error::new_ignored();
+        assert!(error.is_ignored());
+        assert_eq!(error.severity(), Severity::Ignored);
     }
 }
@@ -0,0 +1,56 @@
+use super::error::{Error, Result};
+use ignore::overrides::OverrideBuilder;
+use ignore::{WalkBuilder, WalkState};
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    pub max_depth: Option<usize>,
+    pub hidden: bool,
+    pub overrides: Vec<String>,
+}
+
+/// Walks `root` in parallel, honoring `.gitignore`/`.ignore` files,
+/// `options.overrides` and `options.max_depth`; hidden files are skipped
+/// unless `options.hidden` is set. `visit` is called for every file that is
+/// not filtered out.
+pub fn walk_dir(
+    root: &Path,
+    options: &WalkOptions,
+    visit: impl Fn(&Path) + Send + Sync,
+) -> Result<()> {
+    let mut builder = WalkBuilder::new(root);
+    builder.hidden(!options.hidden);
+    if let Some(max_depth) = options.max_depth {
+        builder.max_depth(Some(max_depth));
+    }
+    if !options.overrides.is_empty() {
+        let mut override_builder = OverrideBuilder::new(root);
+        for glob in &options.overrides {
+            override_builder.add(glob)?;
+        }
+        builder.overrides(override_builder.build()?);
+    }
+
+    let errors = Mutex::new(Vec::new());
+    builder.build_parallel().run(|| {
+        let visit = &visit;
+        let errors = &errors;
+        Box::new(move |entry| {
+            match entry {
+                Ok(entry) if entry.file_type().is_some_and(|t| t.is_file()) => {
+                    visit(entry.path());
+                }
+                Ok(_) => {}
+                Err(error) => errors.lock().unwrap().push(Error::from(error)),
+            }
+            WalkState::Continue
+        })
+    });
+
+    match errors.into_inner().unwrap().into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}